@@ -1,10 +1,630 @@
+use atty;
 use backtrace;
-use std::io::Write;
+use std::collections::VecDeque;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use std::thread;
 
 use time;
 
 use Request;
+use Response;
+
+/// All the information about a request (and, if available, its response) that a `LogFormat`
+/// needs in order to produce one formatted line of output.
+pub struct LogRecord<'a> {
+    /// The HTTP method of the request (eg. `GET`, `POST`, ...).
+    pub method: &'a str,
+    /// The URL that was requested.
+    pub url: &'a str,
+    /// The address of the client that made the request.
+    pub remote_addr: SocketAddr,
+    /// The status code of the response. `0` if the response isn't known yet.
+    pub status_code: u16,
+    /// The number of bytes written in the body of the response.
+    pub bytes_written: u64,
+    /// Time elapsed between the start and the end of the request, in nanoseconds.
+    pub elapsed_ns: u64,
+    /// The value of the request's `Referer` header, if any.
+    pub referer: Option<&'a str>,
+    /// The value of the request's `User-Agent` header, if any.
+    pub user_agent: Option<&'a str>,
+    /// If the request handler panicked, a textual rendering of the panic and its backtrace.
+    pub panic: Option<&'a str>,
+}
+
+/// Coarse severity of a log entry, used by sinks such as `MultiWriter` to decide which
+/// underlying writer(s) an entry is routed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// A request that completed with a non-error status code.
+    Info,
+    /// A request that completed with a `4xx` status code.
+    Warn,
+    /// A request that completed with a `5xx` status code, or whose handler panicked.
+    Error,
+}
+
+fn severity_of(rec: &LogRecord) -> Severity {
+    if rec.panic.is_some() || rec.status_code >= 500 {
+        Severity::Error
+    } else if rec.status_code >= 400 {
+        Severity::Warn
+    } else {
+        Severity::Info
+    }
+}
+
+/// An output that a `LogEntry` can write formatted log lines into.
+///
+/// Blanket-implemented for every `Write`r, which simply ignores the severity. Sinks that care
+/// about severity, such as `MultiWriter`, implement this trait directly instead.
+pub trait LogSink {
+    /// Writes one already-formatted log line to this sink.
+    fn write_record(&mut self, severity: Severity, data: &[u8]) -> io::Result<()>;
+}
+
+impl<W: Write> LogSink for W {
+    fn write_record(&mut self, _severity: Severity, data: &[u8]) -> io::Result<()> {
+        self.write_all(data)
+    }
+}
+
+/// A `LogSink` that fans a log entry out to several underlying writers, each with its own
+/// minimum `Severity`.
+///
+/// ```no_run
+/// use rouille::log::{MultiWriter, Severity};
+/// use std::fs::File;
+///
+/// let sink = MultiWriter::new()
+///     .add_sink(Severity::Info, File::create("access.log").unwrap())
+///     .add_sink(Severity::Error, File::create("errors.log").unwrap());
+/// ```
+pub struct MultiWriter {
+    sinks: Mutex<Vec<(Severity, Box<Write + Send>)>>,
+}
+
+impl MultiWriter {
+    /// Builds an empty `MultiWriter`.
+    pub fn new() -> MultiWriter {
+        MultiWriter { sinks: Mutex::new(Vec::new()) }
+    }
+
+    /// Adds a sink that receives every entry whose severity is at least `min_severity`.
+    pub fn add_sink<W: Write + Send + 'static>(self, min_severity: Severity, sink: W)
+                                                -> MultiWriter {
+        self.sinks.lock().unwrap().push((min_severity, Box::new(sink)));
+        self
+    }
+
+    fn write_locked(&self, severity: Severity, data: &[u8]) -> io::Result<()> {
+        let mut sinks = self.sinks.lock().unwrap();
+
+        for &mut (min_severity, ref mut sink) in sinks.iter_mut() {
+            if severity >= min_severity {
+                sink.write_all(data)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl LogSink for MultiWriter {
+    fn write_record(&mut self, severity: Severity, data: &[u8]) -> io::Result<()> {
+        self.write_locked(severity, data)
+    }
+}
+
+/// Lets a single `MultiWriter` be shared (eg. captured by reference in a `rouille::start_server`
+/// handler closure, which may run concurrently across its worker pool) instead of requiring one
+/// instance per request.
+impl<'a> LogSink for &'a MultiWriter {
+    fn write_record(&mut self, severity: Severity, data: &[u8]) -> io::Result<()> {
+        self.write_locked(severity, data)
+    }
+}
+
+/// A bounded, lock-protected ring buffer of the most recently formatted log lines.
+///
+/// Point a `LogEntry` at a `&LogBuffer` (it implements `LogSink`) to keep the last `capacity`
+/// request log lines in memory, without any unbounded growth or per-request allocation beyond
+/// the line itself. Useful for exposing recent requests (and any captured panics) from a
+/// `/debug/log` endpoint in a headless or embedded deployment, where tailing a file isn't
+/// convenient.
+///
+/// ```no_run
+/// use rouille::log::LogBuffer;
+///
+/// let log_buffer = LogBuffer::new(200);
+///
+/// rouille::start_server("localhost:80", move |request| {
+///     if request.url() == "/debug/log" {
+///         return log_buffer.to_response();
+///     }
+///
+///     let mut entry = rouille::LogEntry::start(&log_buffer, request);
+///     let response = rouille::Response::text("hello world");
+///     entry.finish(&response);
+///     response
+/// });
+/// ```
+pub struct LogBuffer {
+    capacity: usize,
+    lines: Mutex<VecDeque<String>>,
+}
+
+impl LogBuffer {
+    /// Creates an empty `LogBuffer` that keeps at most the `capacity` most recent lines.
+    pub fn new(capacity: usize) -> LogBuffer {
+        LogBuffer {
+            capacity: capacity,
+            lines: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Returns the lines currently held, oldest first.
+    pub fn lines(&self) -> Vec<String> {
+        self.lines.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Renders the buffer's current contents as a plain-text `Response`, suitable for mounting
+    /// at a debug endpoint.
+    pub fn to_response(&self) -> Response {
+        Response::text(self.lines().concat())
+    }
+
+    fn push(&self, line: String) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut lines = self.lines.lock().unwrap();
+
+        if lines.len() == self.capacity {
+            lines.pop_front();
+        }
+
+        lines.push_back(line);
+    }
+}
+
+impl<'a> LogSink for &'a LogBuffer {
+    fn write_record(&mut self, _severity: Severity, data: &[u8]) -> io::Result<()> {
+        self.push(String::from_utf8_lossy(data).into_owned());
+        Ok(())
+    }
+}
+
+/// How often a `RollingFileAppender` rotates its file on a schedule, independently of any
+/// size-based threshold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RotationPolicy {
+    /// Only rotate when `max_size` (if set) is exceeded.
+    Never,
+    /// Rotate at the start of every minute.
+    Minutely,
+    /// Rotate at the start of every hour.
+    Hourly,
+    /// Rotate at midnight every day.
+    Daily,
+}
+
+impl RotationPolicy {
+    // Truncates `now` down to the start of the current rotation period.
+    fn period_start(&self, now: time::Tm) -> time::Tm {
+        let mut truncated = now;
+        truncated.tm_nsec = 0;
+        truncated.tm_sec = 0;
+
+        match *self {
+            RotationPolicy::Never | RotationPolicy::Minutely => {}
+            RotationPolicy::Hourly => truncated.tm_min = 0,
+            RotationPolicy::Daily => {
+                truncated.tm_min = 0;
+                truncated.tm_hour = 0;
+            }
+        }
+
+        truncated
+    }
+}
+
+/// A `Write`r that appends to a file, automatically rotating it once it grows past a
+/// configurable size and/or on a time schedule. Rolled files are renamed with a timestamp
+/// suffix, and only the `max_backups` most recent ones (if set) are kept.
+///
+/// ```no_run
+/// use rouille::log::{RollingFileAppender, RotationPolicy};
+///
+/// let appender = RollingFileAppender::new("access.log", RotationPolicy::Daily)
+///     .unwrap()
+///     .with_max_size(64 * 1024 * 1024)
+///     .with_max_backups(14);
+/// ```
+pub struct RollingFileAppender {
+    path: PathBuf,
+    policy: RotationPolicy,
+    max_size: Option<u64>,
+    max_backups: Option<usize>,
+    state: Mutex<RollingFileState>,
+}
+
+// The parts of a `RollingFileAppender` that change on every write or rotation, behind a single
+// `Mutex` so that rotation (which involves an `fs::rename`) is serialized even when the appender
+// is shared by reference across a concurrent worker pool. Without this, two request threads can
+// both decide rotation is due and race to rename the same path out from under each other.
+struct RollingFileState {
+    file: File,
+    written: u64,
+    period_start: time::Tm,
+}
+
+impl RollingFileAppender {
+    /// Opens (creating if necessary) the file at `path`, rotating it on the given schedule.
+    pub fn new<P: Into<PathBuf>>(path: P, policy: RotationPolicy)
+                                  -> io::Result<RollingFileAppender> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+
+        Ok(RollingFileAppender {
+            path: path,
+            policy: policy,
+            max_size: None,
+            max_backups: None,
+            state: Mutex::new(RollingFileState {
+                file: file,
+                written: written,
+                period_start: policy.period_start(time::now()),
+            }),
+        })
+    }
+
+    /// Also rotates the file as soon as its size reaches `bytes`.
+    pub fn with_max_size(mut self, bytes: u64) -> RollingFileAppender {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// Keeps only the `count` most recently rolled files, deleting older ones on rotation.
+    pub fn with_max_backups(mut self, count: usize) -> RollingFileAppender {
+        self.max_backups = Some(count);
+        self
+    }
+
+    fn rotation_due(&self, state: &RollingFileState) -> bool {
+        if let Some(max_size) = self.max_size {
+            if state.written >= max_size {
+                return true;
+            }
+        }
+
+        self.policy != RotationPolicy::Never && self.policy.period_start(time::now()) != state.period_start
+    }
+
+    fn rotate(&self, state: &mut RollingFileState) -> io::Result<()> {
+        let timestamp = time::now().strftime("%Y%m%d%H%M%S").unwrap().to_string();
+        let base_name = format!("{}.{}", self.path.display(), timestamp);
+
+        // Two rotations can land in the same wall-clock second (eg. back-to-back size-based
+        // rotations on a busy server), and `strftime` only gives us second resolution. Append a
+        // disambiguating suffix rather than letting the second rotation silently overwrite the
+        // first one's rolled file.
+        let mut rolled_name = PathBuf::from(&base_name);
+        let mut suffix = 1;
+        while rolled_name.exists() {
+            rolled_name = PathBuf::from(format!("{}.{}", base_name, suffix));
+            suffix += 1;
+        }
+
+        fs::rename(&self.path, &rolled_name)?;
+
+        state.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        state.written = 0;
+        state.period_start = self.policy.period_start(time::now());
+
+        if let Some(max_backups) = self.max_backups {
+            self.prune(max_backups)?;
+        }
+
+        Ok(())
+    }
+
+    // Deletes all but the `keep` most recently rolled files belonging to this appender.
+    fn prune(&self, keep: usize) -> io::Result<()> {
+        let dir = self.path.parent().unwrap_or_else(|| Path::new("."));
+        let file_name = match self.path.file_name() {
+            Some(n) => n.to_string_lossy().into_owned(),
+            None => return Ok(()),
+        };
+        let prefix = format!("{}.", file_name);
+
+        let mut rolled: Vec<PathBuf> = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|p| {
+                p.file_name()
+                 .map(|n| n.to_string_lossy().starts_with(&prefix))
+                 .unwrap_or(false)
+            })
+            .collect();
+
+        rolled.sort();
+
+        if rolled.len() > keep {
+            for old in &rolled[..rolled.len() - keep] {
+                let _ = fs::remove_file(old);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_locked(&self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+
+        if self.rotation_due(&state) {
+            self.rotate(&mut state)?;
+        }
+
+        let written = state.file.write(buf)?;
+        state.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush_locked(&self) -> io::Result<()> {
+        self.state.lock().unwrap().file.flush()
+    }
+}
+
+impl Write for RollingFileAppender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_locked(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_locked()
+    }
+}
+
+/// Lets a single `RollingFileAppender` be shared (eg. captured by reference in a
+/// `rouille::start_server` handler closure, which may run concurrently across its worker pool)
+/// instead of requiring one instance per request. Rotation is serialized through an internal
+/// lock, so concurrent writers never race on the underlying `fs::rename`.
+impl<'a> LogSink for &'a RollingFileAppender {
+    fn write_record(&mut self, _severity: Severity, data: &[u8]) -> io::Result<()> {
+        self.write_locked(data)?;
+        Ok(())
+    }
+}
+
+/// Turns a `LogRecord` into bytes written to some `Write`r.
+///
+/// Implement this trait to plug a custom access-log format into `LogEntry`. See `CommonLog`,
+/// `CombinedLog` and `JsonLog` for the formats that ship with rouille.
+pub trait LogFormat {
+    /// Writes one log entry corresponding to `rec` to `out`.
+    fn write_entry<W: Write>(&self, out: &mut W, rec: &LogRecord) -> Result<(), ::std::io::Error>;
+
+    /// Whether it's safe to wrap this format in `ColorLog`.
+    ///
+    /// Structured formats (eg. `JsonLog`) must return `false`: appending ANSI escape codes after
+    /// their closing delimiter would produce a line that no longer parses as the structured
+    /// format it claims to be. Text formats meant for a human to read in a terminal return
+    /// `true`, which is also the default.
+    fn is_colorizable(&self) -> bool {
+        true
+    }
+}
+
+/// The NCSA Common Log Format:
+///
+/// ```text
+/// host - - [day/month/year:hour:minute:second zone] "method url HTTP/version" status size
+/// ```
+pub struct CommonLog;
+
+impl CommonLog {
+    fn write_common_prefix<W: Write>(&self, out: &mut W, rec: &LogRecord)
+                                      -> Result<(), ::std::io::Error> {
+        write!(out, "{} - - [{}] \"{} {} HTTP/1.1\" {} {}",
+               rec.remote_addr.ip(),
+               time::now().strftime("%d/%b/%Y:%H:%M:%S %z").unwrap(),
+               rec.method,
+               rec.url,
+               rec.status_code,
+               rec.bytes_written)
+    }
+}
+
+impl LogFormat for CommonLog {
+    fn write_entry<W: Write>(&self, out: &mut W, rec: &LogRecord) -> Result<(), ::std::io::Error> {
+        self.write_common_prefix(out, rec)?;
+
+        if let Some(panic) = rec.panic {
+            write!(out, " PANIC!\n{}", panic)?;
+        }
+
+        writeln!(out, "")
+    }
+}
+
+/// The Combined Log Format, which is the Common Log Format plus the `Referer` and `User-Agent`
+/// headers.
+pub struct CombinedLog;
+
+impl LogFormat for CombinedLog {
+    fn write_entry<W: Write>(&self, out: &mut W, rec: &LogRecord) -> Result<(), ::std::io::Error> {
+        CommonLog.write_common_prefix(out, rec)?;
+
+        write!(out, " \"{}\" \"{}\"",
+               rec.referer.unwrap_or("-"),
+               rec.user_agent.unwrap_or("-"))?;
+
+        if let Some(panic) = rec.panic {
+            write!(out, " PANIC!\n{}", panic)?;
+        }
+
+        writeln!(out, "")
+    }
+}
+
+/// One JSON object per line, for ingestion by log shippers.
+pub struct JsonLog;
+
+impl LogFormat for JsonLog {
+    fn write_entry<W: Write>(&self, out: &mut W, rec: &LogRecord) -> Result<(), ::std::io::Error> {
+        write!(out, "{{\"method\":\"{}\",\"url\":\"{}\",\"remote_addr\":\"{}\",\
+                      \"status_code\":{},\"bytes_written\":{},\"elapsed_ns\":{}",
+               json_escape(rec.method),
+               json_escape(rec.url),
+               rec.remote_addr,
+               rec.status_code,
+               rec.bytes_written,
+               rec.elapsed_ns)?;
+
+        match rec.referer {
+            Some(referer) => write!(out, ",\"referer\":\"{}\"", json_escape(referer))?,
+            None => write!(out, ",\"referer\":null")?,
+        }
+
+        match rec.user_agent {
+            Some(user_agent) => write!(out, ",\"user_agent\":\"{}\"", json_escape(user_agent))?,
+            None => write!(out, ",\"user_agent\":null")?,
+        }
+
+        if let Some(panic) = rec.panic {
+            write!(out, ",\"panic\":\"{}\"", json_escape(panic))?;
+        }
+
+        writeln!(out, "}}")
+    }
+
+    fn is_colorizable(&self) -> bool {
+        // Appending ANSI codes after the closing `}` would break JSON parsers.
+        false
+    }
+}
+
+fn json_escape(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Controls whether `ColorLog` emits ANSI escape codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorMode {
+    /// Colorize only if the process's stdout looks like it's connected to a terminal.
+    ///
+    /// This only makes sense when the `LogEntry`'s output is in fact stdout; for any other
+    /// sink (a file, a `RollingFileAppender`, a `LogBuffer`, ...) use `Always` or `Never`
+    /// explicitly.
+    Auto,
+    /// Always emit ANSI escape codes, regardless of what the output is connected to.
+    Always,
+    /// Never emit ANSI escape codes.
+    Never,
+}
+
+impl ColorMode {
+    fn enabled(&self) -> bool {
+        match *self {
+            ColorMode::Auto => atty::is(atty::Stream::Stdout),
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+        }
+    }
+}
+
+const ANSI_RESET: &'static str = "\x1b[0m";
+const ANSI_DIM: &'static str = "\x1b[2m";
+
+fn ansi_color_for(rec: &LogRecord) -> &'static str {
+    if rec.panic.is_some() || rec.status_code >= 500 {
+        "\x1b[31m" // red
+    } else if rec.status_code >= 400 {
+        "\x1b[33m" // yellow
+    } else if rec.status_code >= 300 {
+        "\x1b[36m" // cyan
+    } else {
+        "\x1b[32m" // green
+    }
+}
+
+fn format_elapsed(ns: u64) -> String {
+    if ns < 1_000 {
+        format!("{}ns", ns)
+    } else if ns < 1_000_000 {
+        format!("{:.1}us", ns as f64 / 1_000.0)
+    } else if ns < 1_000_000_000 {
+        format!("{:.1}ms", ns as f64 / 1_000_000.0)
+    } else {
+        format!("{:.1}s", ns as f64 / 1_000_000_000.0)
+    }
+}
+
+/// Wraps another `LogFormat` and, when writing to a terminal, color-codes each entry by status
+/// (2xx green, 3xx cyan, 4xx yellow, 5xx/panics red) with a dimmed elapsed-time field appended,
+/// making dev-server output easier to scan at a glance. Piping to a file keeps the wrapped
+/// format's plain, parseable output by passing `ColorMode::Never` (or simply not wrapping it).
+///
+/// Structured formats such as `JsonLog` report themselves as non-colorizable, so wrapping one in
+/// `ColorLog` is a no-op that always falls through to the inner format unchanged, rather than
+/// appending ANSI codes after the closing `}` and breaking JSON parsers downstream.
+///
+/// ```no_run
+/// use rouille::log::{ColorLog, ColorMode, CommonLog};
+///
+/// let format = ColorLog::new(CommonLog, ColorMode::Auto);
+/// # let _ = format;
+/// ```
+pub struct ColorLog<F> {
+    inner: F,
+    mode: ColorMode,
+}
+
+impl<F: LogFormat> ColorLog<F> {
+    /// Wraps `inner`, colorizing its output according to `mode`.
+    pub fn new(inner: F, mode: ColorMode) -> ColorLog<F> {
+        ColorLog { inner: inner, mode: mode }
+    }
+}
+
+impl<F: LogFormat> LogFormat for ColorLog<F> {
+    fn write_entry<W: Write>(&self, out: &mut W, rec: &LogRecord) -> Result<(), ::std::io::Error> {
+        if !self.mode.enabled() || !self.inner.is_colorizable() {
+            return self.inner.write_entry(out, rec);
+        }
+
+        let mut buf = Vec::new();
+        self.inner.write_entry(&mut buf, rec)?;
+
+        while buf.last() == Some(&b'\n') || buf.last() == Some(&b'\r') {
+            buf.pop();
+        }
+
+        write!(out, "{}", ansi_color_for(rec))?;
+        out.write_all(&buf)?;
+        writeln!(out, "{} {}{}{}", ANSI_RESET, ANSI_DIM, format_elapsed(rec.elapsed_ns), ANSI_RESET)
+    }
+}
 
 /// RAII guard that ensures that a log entry corresponding to a request will be written.
 ///
@@ -12,38 +632,100 @@ use Request;
 ///
 /// ```no_run
 /// rouille::start_server("localhost:80", move |request| {
-///     let _entry = rouille::LogEntry::start(std::io::stdout(), request);
+///     let mut entry = rouille::LogEntry::start(std::io::stdout(), request);
 ///
 ///     // process the request here
+///     let response = rouille::Response::text("hello world");
 ///
-/// # panic!()
-/// }); // <-- the log entry is written at the end of this block
+///     entry.finish(&response);
+///     response
+/// });
 /// ```
 ///
-pub struct LogEntry<W> where W: Write {
-    line: String,
+/// If the handler panics before `finish` is called, the `Drop` implementation still writes an
+/// entry recording the panic and its backtrace.
+pub struct LogEntry<W, F = CommonLog> where W: LogSink, F: LogFormat {
+    method: String,
+    url: String,
+    remote_addr: SocketAddr,
+    referer: Option<String>,
+    user_agent: Option<String>,
     output: W,
+    format: F,
     start_time: u64,
+    // Set by `finish()`. `None` means the handler never called `finish()`, which only
+    // legitimately happens on the panicking path (where `Drop` reports the panic instead).
+    response: Option<(u16, u64)>,
 }
 
-impl<'a, W> LogEntry<W> where W: Write {
-    /// Starts a `LogEntry`.
-    pub fn start(output: W, rq: &Request) -> LogEntry<W> {
+impl<W> LogEntry<W, CommonLog> where W: LogSink {
+    /// Starts a `LogEntry`, formatting the access-log line with `CommonLog`.
+    pub fn start(output: W, rq: &Request) -> LogEntry<W, CommonLog> {
+        LogEntry::start_with_format(output, rq, CommonLog)
+    }
+}
+
+impl<W, F> LogEntry<W, F> where W: LogSink, F: LogFormat {
+    /// Starts a `LogEntry`, formatting the access-log line with the given `LogFormat`.
+    pub fn start_with_format(output: W, rq: &Request, format: F) -> LogEntry<W, F> {
         LogEntry {
-            line: format!("GET {}", rq.url()),       // TODO: 
+            method: rq.method().to_owned(),
+            url: rq.url(),
+            remote_addr: *rq.remote_addr(),
+            referer: rq.header("Referer").map(|h| h.to_owned()),
+            user_agent: rq.header("User-Agent").map(|h| h.to_owned()),
             output: output,
+            format: format,
             start_time: time::precise_time_ns(),
+            response: None,
         }
     }
+
+    /// Records the response that was sent back for this request, and writes the access-log
+    /// line straight away.
+    ///
+    /// Call this at the end of the request handler, once the `Response` has been built. If the
+    /// handler panics before `finish` is reached, `Drop` takes care of writing a log entry with
+    /// the panic backtrace instead.
+    pub fn finish(&mut self, response: &Response) {
+        // The body may be streamed out without its full length being known in advance; when
+        // that's the case we report `0` rather than guessing.
+        let bytes_written = response.headers.iter()
+                                             .find(|&&(ref name, _)| name.eq_ignore_ascii_case("Content-Length"))
+                                             .and_then(|&(_, ref value)| value.parse().ok())
+                                             .unwrap_or(0u64);
+        self.response = Some((response.status_code, bytes_written));
+
+        let elapsed = time::precise_time_ns() - self.start_time;
+        let rec = LogRecord {
+            method: &self.method,
+            url: &self.url,
+            remote_addr: self.remote_addr,
+            status_code: response.status_code,
+            bytes_written: bytes_written,
+            elapsed_ns: elapsed,
+            referer: self.referer.as_ref().map(|s| s.as_str()),
+            user_agent: self.user_agent.as_ref().map(|s| s.as_str()),
+            panic: None,
+        };
+
+        format_and_route(&self.format, &mut self.output, &rec).unwrap();
+    }
 }
 
-impl<W> Drop for LogEntry<W> where W: Write {
+impl<W, F> Drop for LogEntry<W, F> where W: LogSink, F: LogFormat {
     fn drop(&mut self) {
-        write!(self.output, "{} - ", self.line).unwrap();
+        // `finish()` already wrote the real access-log entry, whether or not the handler goes
+        // on to panic afterwards (eg. in code that runs between `finish()` and returning). Don't
+        // write a second, misleading entry with no response information on top of it.
+        if self.response.is_some() {
+            return;
+        }
 
-        if thread::panicking() {
-            writeln!(self.output, " - PANIC!").unwrap();
+        let elapsed = time::precise_time_ns() - self.start_time;
 
+        if thread::panicking() {
+            let mut panic = String::new();
             let mut frame_num = 0;
 
             backtrace::trace(&mut |frame| {
@@ -51,41 +733,307 @@ impl<W> Drop for LogEntry<W> where W: Write {
                 frame_num += 1;
 
                 backtrace::resolve(ip, &mut |symbol| {
-                    let name = String::from_utf8(symbol.name()
-                                                       .unwrap_or(&b"<unknown>"[..])
-                                                       .to_owned())
-                                       .unwrap_or_else(|_| "<not-utf8>".to_owned());
-                    let filename = String::from_utf8(symbol.filename()
-                                                           .unwrap_or(&b"<unknown>"[..])
-                                                           .to_owned())
-                                           .unwrap_or_else(|_| "<not-utf8>".to_owned());
+                    // Use the lossy conversion rather than rejecting non-UTF-8 bytes outright:
+                    // a mangled symbol or a filename on a non-UTF-8 filesystem is still far more
+                    // useful printed with replacement characters than discarded entirely.
+                    let name = String::from_utf8_lossy(symbol.name()
+                                                             .unwrap_or(&b"<unknown>"[..]))
+                                       .into_owned();
+                    let filename = String::from_utf8_lossy(symbol.filename()
+                                                                 .unwrap_or(&b"<unknown>"[..]))
+                                           .into_owned();
                     let line = symbol.lineno().map(|l| l.to_string())
                                               .unwrap_or_else(|| "??".to_owned());
 
-                    writeln!(self.output, "{:>#4} - {:p} - {}\n       {}:{}",
-                             frame_num, ip, name, filename, line).unwrap();
+                    panic.push_str(&format!("{:>#4} - {:p} - {}\n       {}:{}\n",
+                                             frame_num, ip, name, filename, line));
                 });
 
                 true
             });
 
+            let rec = LogRecord {
+                method: &self.method,
+                url: &self.url,
+                remote_addr: self.remote_addr,
+                status_code: 0,
+                bytes_written: 0,
+                elapsed_ns: elapsed,
+                referer: self.referer.as_ref().map(|s| s.as_str()),
+                user_agent: self.user_agent.as_ref().map(|s| s.as_str()),
+                panic: Some(&panic),
+            };
+
+            // We're already unwinding from a panic: a write failure here must not itself
+            // panic, or the process aborts instead of reporting the original panic.
+            let _ = format_and_route(&self.format, &mut self.output, &rec);
         } else {
-            let elapsed = time::precise_time_ns() - self.start_time;
-            format_time(self.output.by_ref(), elapsed);
-        }
+            // The handler never called `finish()`: fall back to a best-effort entry with no
+            // response information.
+            let rec = LogRecord {
+                method: &self.method,
+                url: &self.url,
+                remote_addr: self.remote_addr,
+                status_code: 0,
+                bytes_written: 0,
+                elapsed_ns: elapsed,
+                referer: self.referer.as_ref().map(|s| s.as_str()),
+                user_agent: self.user_agent.as_ref().map(|s| s.as_str()),
+                panic: None,
+            };
 
-        writeln!(self.output, "").unwrap();
+            format_and_route(&self.format, &mut self.output, &rec).unwrap();
+        }
     }
 }
 
-fn format_time<W>(mut out: W, time: u64) where W: Write {
-    if time < 1_000 {
-        write!(out, "{}ns", time).unwrap()
-    } else if time < 1_000_000 {
-        write!(out, "{:.1}us", time as f64 / 1_000.0).unwrap()
-    } else if time < 1_000_000_000 {
-        write!(out, "{:.1}ms", time as f64 / 1_000_000.0).unwrap()
-    } else {
-        write!(out, "{:.1}s", time as f64 / 1_000_000_000.0).unwrap()
+fn format_and_route<F: LogFormat, W: LogSink>(format: &F, output: &mut W, rec: &LogRecord)
+                                               -> io::Result<()> {
+    let mut line = Vec::new();
+    format.write_entry(&mut line, rec)?;
+    output.write_record(severity_of(rec), &line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_buffer_keeps_only_the_most_recent_lines() {
+        let buffer = LogBuffer::new(2);
+        buffer.push("one".to_owned());
+        buffer.push("two".to_owned());
+        buffer.push("three".to_owned());
+
+        assert_eq!(buffer.lines(), vec!["two".to_owned(), "three".to_owned()]);
+    }
+
+    #[test]
+    fn zero_capacity_log_buffer_stores_nothing() {
+        let buffer = LogBuffer::new(0);
+        buffer.push("one".to_owned());
+        buffer.push("two".to_owned());
+
+        assert!(buffer.lines().is_empty());
+    }
+
+    #[test]
+    fn drop_does_not_double_log_after_finish_even_when_panicking() {
+        let rq = Request::fake_http("GET", "/", vec![], vec![]);
+        let buffer = LogBuffer::new(4);
+
+        let result = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            let mut entry = LogEntry::start(&buffer, &rq);
+            let response = Response::text("ok");
+            entry.finish(&response);
+            panic!("boom");
+        }));
+
+        assert!(result.is_err());
+        assert_eq!(buffer.lines().len(), 1);
+    }
+
+    #[test]
+    fn json_escape_escapes_quotes_backslashes_and_control_characters() {
+        assert_eq!(json_escape("tab\there \"quoted\"\\n"), "tab\\there \\\"quoted\\\"\\\\n");
+        assert_eq!(json_escape("line\nbreak\r"), "line\\nbreak\\r");
+        assert_eq!(json_escape("plain"), "plain");
+    }
+
+    #[test]
+    fn combined_log_falls_back_to_dashes_when_headers_are_absent() {
+        let rq = Request::fake_http("GET", "/", vec![], vec![]);
+        let buffer = LogBuffer::new(1);
+
+        let mut entry = LogEntry::start_with_format(&buffer, &rq, CombinedLog);
+        entry.finish(&Response::text("ok"));
+
+        let lines = buffer.lines();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains("\"-\" \"-\""));
+    }
+
+    #[test]
+    fn combined_log_includes_referer_and_user_agent_headers() {
+        let rq = Request::fake_http("GET", "/", vec![
+            ("Referer".to_owned(), "https://example.com/".to_owned()),
+            ("User-Agent".to_owned(), "test-agent/1.0".to_owned()),
+        ], vec![]);
+        let buffer = LogBuffer::new(1);
+
+        let mut entry = LogEntry::start_with_format(&buffer, &rq, CombinedLog);
+        entry.finish(&Response::text("ok"));
+
+        let lines = buffer.lines();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains("\"https://example.com/\" \"test-agent/1.0\""));
+    }
+
+    #[test]
+    fn json_log_includes_referer_and_user_agent_or_null_when_absent() {
+        let rq_with_headers = Request::fake_http("GET", "/", vec![
+            ("Referer".to_owned(), "https://example.com/".to_owned()),
+            ("User-Agent".to_owned(), "test-agent/1.0".to_owned()),
+        ], vec![]);
+        let buffer = LogBuffer::new(2);
+
+        let mut entry = LogEntry::start_with_format(&buffer, &rq_with_headers, JsonLog);
+        entry.finish(&Response::text("ok"));
+
+        let rq_without_headers = Request::fake_http("GET", "/", vec![], vec![]);
+        let mut entry = LogEntry::start_with_format(&buffer, &rq_without_headers, JsonLog);
+        entry.finish(&Response::text("ok"));
+
+        let lines = buffer.lines();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("\"referer\":\"https://example.com/\""));
+        assert!(lines[0].contains("\"user_agent\":\"test-agent/1.0\""));
+        assert!(lines[1].contains("\"referer\":null"));
+        assert!(lines[1].contains("\"user_agent\":null"));
+    }
+
+    // Gives each rotation test its own directory under `std::env::temp_dir()`, so concurrent
+    // test runs never trip over each other's log files.
+    fn rolling_test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rouille-log-test-{}-{}", name, time::precise_time_ns()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn rotation_due_when_max_size_exceeded() {
+        let dir = rolling_test_dir("max-size");
+        let path = dir.join("access.log");
+
+        let appender = RollingFileAppender::new(&path, RotationPolicy::Never)
+            .unwrap()
+            .with_max_size(4);
+
+        appender.write_locked(b"12345").unwrap();
+
+        let state = appender.state.lock().unwrap();
+        assert!(appender.rotation_due(&state));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn rotate_renames_the_old_file_and_resets_the_byte_count() {
+        let dir = rolling_test_dir("rotate");
+        let path = dir.join("access.log");
+
+        let appender = RollingFileAppender::new(&path, RotationPolicy::Never)
+            .unwrap()
+            .with_max_size(1);
+
+        appender.write_locked(b"hello").unwrap();
+        appender.write_locked(b"world").unwrap();
+
+        let rolled_files: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|p| p != &path)
+            .collect();
+
+        assert_eq!(rolled_files.len(), 1);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "world");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn rotate_disambiguates_two_rotations_in_the_same_second() {
+        let dir = rolling_test_dir("rotate-collision");
+        let path = dir.join("access.log");
+
+        let appender = RollingFileAppender::new(&path, RotationPolicy::Never)
+            .unwrap()
+            .with_max_size(1);
+
+        // No sleep between writes: both rotations happen within the same wall-clock second, so
+        // the rolled filenames (second-resolution timestamps) would collide without
+        // disambiguation.
+        appender.write_locked(b"one").unwrap();
+        appender.write_locked(b"two").unwrap();
+        appender.write_locked(b"three").unwrap();
+
+        let rolled_files: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|p| p != &path)
+            .collect();
+
+        assert_eq!(rolled_files.len(), 2);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "three");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn prune_keeps_only_the_most_recent_backups() {
+        let dir = rolling_test_dir("prune");
+        let path = dir.join("access.log");
+
+        let appender = RollingFileAppender::new(&path, RotationPolicy::Never)
+            .unwrap()
+            .with_max_size(1)
+            .with_max_backups(1);
+
+        appender.write_locked(b"a").unwrap();
+        thread::sleep(::std::time::Duration::from_millis(1100));
+        appender.write_locked(b"b").unwrap();
+        thread::sleep(::std::time::Duration::from_millis(1100));
+        appender.write_locked(b"c").unwrap();
+
+        let rolled_files: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|p| p != &path)
+            .collect();
+
+        assert_eq!(rolled_files.len(), 1);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    fn sample_record<'a>() -> LogRecord<'a> {
+        LogRecord {
+            method: "GET",
+            url: "/",
+            remote_addr: "127.0.0.1:1234".parse().unwrap(),
+            status_code: 200,
+            bytes_written: 0,
+            elapsed_ns: 0,
+            referer: None,
+            user_agent: None,
+            panic: None,
+        }
+    }
+
+    #[test]
+    fn color_log_wraps_the_inner_line_in_ansi_codes_without_duplicating_the_newline() {
+        let format = ColorLog::new(CommonLog, ColorMode::Always);
+        let mut out = Vec::new();
+        format.write_entry(&mut out, &sample_record()).unwrap();
+
+        let line = String::from_utf8(out).unwrap();
+        assert!(line.starts_with("\x1b["));
+        assert!(line.trim_end().ends_with(ANSI_RESET));
+        assert_eq!(line.matches('\n').count(), 1);
+    }
+
+    #[test]
+    fn color_log_is_a_no_op_for_non_colorizable_formats_like_json() {
+        let colored = ColorLog::new(JsonLog, ColorMode::Always);
+        let mut colored_out = Vec::new();
+        colored.write_entry(&mut colored_out, &sample_record()).unwrap();
+
+        let mut plain_out = Vec::new();
+        JsonLog.write_entry(&mut plain_out, &sample_record()).unwrap();
+
+        assert_eq!(colored_out, plain_out);
     }
 }